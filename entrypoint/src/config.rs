@@ -1,5 +1,130 @@
+use nix::sys::signal::Signal;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 
+/// Signals the client will proxy to the server over the control socket by
+/// default. The order here has no effect on the pending-signals bitmask:
+/// each signal's bit is fixed by its position in `main::FORWARDABLE_SIGNALS`.
+///
+/// Conservative by default: `SIGTSTP` (job control, i.e. Ctrl-Z) is left
+/// out since suspending the client without suspending the server it's
+/// fronting is surprising unless a caller opts in.
+pub fn default_forwarded_signals() -> Vec<Signal> {
+    vec![
+        Signal::SIGINT,
+        Signal::SIGTERM,
+        Signal::SIGQUIT,
+        Signal::SIGWINCH,
+    ]
+}
+
+/// Where the control socket lives.
+///
+/// Resolved at runtime so more than one clavier server can run on a single
+/// machine (or host on loopback TCP) instead of always binding the
+/// compile-time default baked in by `server::SOCKET_PATH`.
+pub enum Endpoint {
+    /// A regular filesystem Unix domain socket.
+    UnixPath(PathBuf),
+    /// A Linux abstract-namespace Unix domain socket (no backing file).
+    AbstractUnix(Vec<u8>),
+    /// A plain TCP socket, typically on loopback.
+    Tcp(SocketAddr),
+}
+
+impl Endpoint {
+    /// Resolve an `Endpoint` from the environment variable `var`, falling
+    /// back to `default` if it's unset or fails to parse.
+    pub fn from_env(var: &str, default: Endpoint) -> Endpoint {
+        match env::var(var) {
+            Ok(value) => Endpoint::parse(&value).unwrap_or_else(|| {
+                warn!(
+                    "Failed to parse {} as an endpoint, ignoring: {:?}",
+                    var, value
+                );
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Parse an endpoint from a string.
+    ///
+    /// An abstract socket is written with a leading escaped NUL byte, the
+    /// way `std::ascii::escape_default` would escape it, e.g.
+    /// `\x00clavier.sock`. Anything that parses as a `SocketAddr` (e.g.
+    /// `127.0.0.1:9999`) is treated as TCP; everything else is a filesystem
+    /// path.
+    pub fn parse(s: &str) -> Option<Endpoint> {
+        if let Some(escaped_name) = s.strip_prefix("\\x00") {
+            return unescape_ascii(escaped_name).map(Endpoint::AbstractUnix);
+        }
+
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Some(Endpoint::Tcp(addr));
+        }
+
+        Some(Endpoint::UnixPath(PathBuf::from(s)))
+    }
+}
+
+/// Render an abstract-socket name the way users are expected to type it:
+/// a leading escaped NUL followed by each byte run through
+/// `std::ascii::escape_default`.
+pub fn escape_abstract_name(name: &[u8]) -> String {
+    let mut escaped = String::from("\\x00");
+    for byte in name {
+        escaped.extend(std::ascii::escape_default(*byte).map(char::from));
+    }
+    escaped
+}
+
+/// Inverse of `escape_abstract_name` (minus the leading `\x00`, which the
+/// caller strips off first since it marks the string as an abstract name
+/// rather than being part of it).
+fn unescape_ascii(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        match bytes.get(i + 1) {
+            Some(b'x') => {
+                let hex = s.get(i + 2..i + 4)?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 4;
+            }
+            Some(b'n') => {
+                out.push(b'\n');
+                i += 2;
+            }
+            Some(b'r') => {
+                out.push(b'\r');
+                i += 2;
+            }
+            Some(b't') => {
+                out.push(b'\t');
+                i += 2;
+            }
+            Some(&other) => {
+                out.push(other);
+                i += 2;
+            }
+            None => return None,
+        }
+    }
+
+    Some(out)
+}
+
 pub enum BackoffType {
     Constant,
     // Linear,
@@ -40,16 +165,47 @@ impl RetryConfig {
     }
 }
 
+/// Env var users can set to point at a non-default socket endpoint, e.g.
+/// `127.0.0.1:9999` or `\x00clavier.sock` for an abstract socket.
+pub const SOCKET_ENDPOINT_VAR: &str = "ENTRYPOINT_SOCKET_ADDR";
+
 pub struct Config {
     pub kill_server: RetryConfig,
     pub connect_server: RetryConfig,
+    /// Retry budget for `server::restart_graceful`'s wait for the PID file's
+    /// generation counter to advance past a `SIGHUP`. A full re-exec (new
+    /// process image, runtime init, re-binding the poller) routinely takes
+    /// longer than `connect_server`'s few-quick-retries budget is tuned
+    /// for, so this gets its own, longer one.
+    pub restart_graceful: RetryConfig,
+    pub forwarded_signals: Vec<Signal>,
+    /// Require that the process on the other end of the control socket
+    /// shares our UID (checked via `SO_PEERCRED`) before trusting it.
+    /// Defaults to `true` for Unix sockets, which are the case this
+    /// protects (a socket squatted by another user). TCP has no
+    /// `SO_PEERCRED` equivalent, so it defaults to `false` -- callers
+    /// wanting auth over TCP need their own scheme.
+    pub require_same_uid: bool,
 }
 
 impl Config {
+    /// `endpoint` is only consulted to pick `require_same_uid`'s default --
+    /// the endpoint itself lives on `server::ServerContext`, since it's
+    /// part of a server's identity rather than how the client behaves
+    /// towards it.
     pub fn new(
         kill_server: Option<RetryConfig>,
         connect_server: Option<RetryConfig>,
+        endpoint: &Endpoint,
+        forwarded_signals: Option<Vec<Signal>>,
+        require_same_uid: Option<bool>,
+        restart_graceful: Option<RetryConfig>,
     ) -> Config {
+        let require_same_uid = require_same_uid.unwrap_or(matches!(
+            endpoint,
+            Endpoint::UnixPath(_) | Endpoint::AbstractUnix(_)
+        ));
+
         Config {
             kill_server: kill_server.unwrap_or(RetryConfig::default()),
             connect_server: connect_server.unwrap_or(RetryConfig::new(
@@ -57,6 +213,58 @@ impl Config {
                 Some(BackoffType::Constant),
                 Some(Duration::from_millis(10)),
             )),
+            restart_graceful: restart_graceful.unwrap_or(RetryConfig::new(
+                Some(10),
+                Some(BackoffType::Constant),
+                Some(Duration::from_millis(250)),
+            )),
+            forwarded_signals: forwarded_signals.unwrap_or_else(default_forwarded_signals),
+            require_same_uid,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_unix_path() {
+        match Endpoint::parse("/tmp/clavier.sock") {
+            Some(Endpoint::UnixPath(path)) => assert_eq!(path, PathBuf::from("/tmp/clavier.sock")),
+            other => panic!("Expected UnixPath, got {:?}", other.is_some()),
+        }
+    }
+
+    #[test]
+    fn parse_tcp() {
+        match Endpoint::parse("127.0.0.1:9999") {
+            Some(Endpoint::Tcp(addr)) => assert_eq!(addr.port(), 9999),
+            other => panic!("Expected Tcp, got {:?}", other.is_some()),
+        }
+    }
+
+    #[test]
+    fn parse_abstract_unix_round_trips_escape_abstract_name() {
+        let name = b"clavier.sock".to_vec();
+        let escaped = escape_abstract_name(&name);
+
+        match Endpoint::parse(&escaped) {
+            Some(Endpoint::AbstractUnix(parsed)) => assert_eq!(parsed, name),
+            other => panic!("Expected AbstractUnix, got {:?}", other.is_some()),
+        }
+    }
+
+    #[test]
+    fn parse_abstract_unix_rejects_truncated_hex_escape() {
+        // "\x0" is missing the second hex digit, so `unescape_ascii` should
+        // bail rather than read out of bounds or silently drop a byte.
+        assert!(Endpoint::parse("\\x00cla\\x0").is_none());
+    }
+
+    #[test]
+    fn escape_abstract_name_handles_non_ascii_bytes() {
+        let escaped = escape_abstract_name(&[0x00, b'a', 0xff]);
+        assert_eq!(escaped, "\\x00\\x00a\\xff");
+    }
+}
@@ -1,15 +1,17 @@
+use nix::fcntl::OFlag;
 use nix::libc;
 use nix::sys::signal::{self, SigHandler, Signal};
+use nix::unistd;
+use polling::{Event, Events, Poller};
 use sendfd::{self, SendWithFd};
 use serde_json::json;
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::prelude::*;
+use std::os::fd::{AsRawFd, IntoRawFd, RawFd};
 use std::os::unix::process::CommandExt;
-use std::os::{fd::AsRawFd, fd::RawFd};
 use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
 use std::time::Duration;
 use std::{env, io, process};
 
@@ -25,15 +27,154 @@ use config::Config;
 
 const DOTENV_PATH: Option<&str> = option_env!("ENTRYPOINT_DOTENV_PATH");
 
+/// The full set of signals that can be proxied to the server, fixed so the
+/// bit each one occupies in `PENDING_SIGNALS` never shifts underneath the
+/// handler -- `Config::forwarded_signals` only chooses which of these get
+/// registered, it doesn't change the bit layout.
+const FORWARDABLE_SIGNALS: [Signal; 5] = [
+    Signal::SIGINT,
+    Signal::SIGTERM,
+    Signal::SIGQUIT,
+    Signal::SIGWINCH,
+    Signal::SIGTSTP,
+];
+
+fn signal_bit(signal: Signal) -> Option<u32> {
+    FORWARDABLE_SIGNALS
+        .iter()
+        .position(|s| *s == signal)
+        .map(|i| i as u32)
+}
+
 lazy_static! {
-    static ref SIGNALED: AtomicBool = AtomicBool::new(false);
+    static ref PENDING_SIGNALS: AtomicU32 = AtomicU32::new(0);
 }
 
+/// Write end of the self-pipe used to wake the `Poller` from the signal
+/// handler; `-1` until `main` sets it up. A signal handler can't safely
+/// close over locals, so this is how it finds the fd to write to.
+static SIGNAL_PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
 // https://docs.rs/nix/latest/nix/sys/signal/fn.signal.html
-extern "C" fn handle_sigint(signal: libc::c_int) {
-    let signal = Signal::try_from(signal).unwrap();
+extern "C" fn handle_signal(signal: libc::c_int) {
+    if let Ok(signal) = Signal::try_from(signal) {
+        if let Some(bit) = signal_bit(signal) {
+            PENDING_SIGNALS.fetch_or(1 << bit, Ordering::Relaxed);
+        }
+    }
+
+    let write_fd = SIGNAL_PIPE_WRITE.load(Ordering::Relaxed);
+    if write_fd >= 0 {
+        // A single byte is enough to wake the poller; the value is never
+        // read for meaning, so short writes/EINTR need no retry here.
+        unsafe {
+            libc::write(write_fd, [0u8].as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+/// Drain every byte currently sitting in the self-pipe so the next signal
+/// wakes the poller again instead of it immediately reporting readable.
+fn drain_self_pipe(fd: RawFd) {
+    let mut buf = [0u8; 64];
+    loop {
+        match unistd::read(fd, &mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => continue,
+        }
+    }
+}
+
+/// Forward whatever signals have landed since the last check to the server.
+fn forward_pending_signals(stream: &mut server::Stream) -> Result<(), Box<dyn Error>> {
+    let pending = PENDING_SIGNALS.swap(0, Ordering::Relaxed);
+    for (bit, signal) in FORWARDABLE_SIGNALS.iter().enumerate() {
+        if pending & (1 << bit) != 0 {
+            let sig_num: i32 = *signal as i32;
+            stream.write(&sig_num.to_ne_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Fill `buf` from `stream`, blocking (via the poller, not a busy loop) on
+/// socket or signal readability until it's full. Pending signals are
+/// forwarded to the server as they're noticed along the way.
+fn read_polled(
+    stream: &mut server::Stream,
+    poller: &Poller,
+    events: &mut Events,
+    sig_read_fd: RawFd,
+    socket_key: usize,
+    signal_key: usize,
+    buf: &mut [u8],
+) -> Result<(), Box<dyn Error>> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        events.clear();
+        // The timeout is only a safety net -- every real wakeup comes from
+        // the socket or signal fd becoming readable.
+        poller.wait(events, Some(Duration::from_secs(30)))?;
+
+        for event in events.iter() {
+            if event.key == signal_key {
+                drain_self_pipe(sig_read_fd);
+                forward_pending_signals(stream)?;
+            }
+        }
+
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err("Server closed the connection".into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        poller.modify(stream.as_raw_fd(), Event::readable(socket_key))?;
+        poller.modify(sig_read_fd, Event::readable(signal_key))?;
+    }
+
+    Ok(())
+}
+
+/// Replay a log record the server forwarded through its own `log` setup,
+/// preserving the original level/target/module/line and honoring whatever
+/// filter the client's `RUST_LOG` configured.
+fn replay_log_record(record: server::LogRecord) {
+    let level = match record.level.parse::<log::Level>() {
+        Ok(level) => level,
+        Err(_) => {
+            warn!(
+                "Dropping forwarded log record with unknown level {:?}",
+                record.level
+            );
+            return;
+        }
+    };
+
+    let forwarded = log::Record::builder()
+        .level(level)
+        .target(&record.target)
+        .module_path(record.module_path.as_deref())
+        .line(record.line)
+        .args(format_args!("(server) {}", record.message))
+        .build();
+
+    if log::logger().enabled(forwarded.metadata()) {
+        log::logger().log(&forwarded);
+    }
+}
 
-    SIGNALED.store(signal == Signal::SIGINT, Ordering::Relaxed);
+/// Decode a frame's native-endian length prefix, rejecting negative values
+/// instead of panicking -- a malformed or buggy length from the server
+/// (either the terminal response or any preceding `LogRecord`) shouldn't be
+/// able to crash the client.
+fn parse_frame_length(length_buffer: [u8; 4]) -> Result<usize, Box<dyn Error>> {
+    let frame_length = i32::from_ne_bytes(length_buffer);
+    frame_length
+        .try_into()
+        .map_err(|_| format!("Bad frame length {}", frame_length).into())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -45,7 +186,8 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    let config = Config::new(None, None);
+    let ctx = server::ServerContext::from_env();
+    let config = Config::new(None, None, &ctx.socket_endpoint, None, None, None);
 
     let cwd = env::current_dir()?;
 
@@ -73,14 +215,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     if kill {
-        return server::kill(&config);
+        return server::kill(&ctx, &config);
     }
 
     if restart {
-        server::kill(&config)?;
-        server::create()?;
-    } else if !server::socket_exists() {
-        server::create()?;
+        server::restart_graceful(&ctx, &config)?;
+    } else if !server::socket_exists(&ctx.socket_endpoint) {
+        server::create(&ctx, &config)?;
     }
 
     let payload = json!({
@@ -89,7 +230,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         "cwd": cwd,
     });
 
-    let mut stream = server::connect(&config)?;
+    let mut stream = server::connect(&ctx, &config)?;
 
     let mut fds: Vec<RawFd> = vec![
         io::stdin().as_raw_fd(),
@@ -102,46 +243,93 @@ fn main() -> Result<(), Box<dyn Error>> {
         fds.push(9);
     }
 
-    stream.send_with_fd(payload.to_string().as_bytes(), &fds[..])?;
-
-    let handler = SigHandler::Handler(handle_sigint);
-    unsafe { signal::signal(Signal::SIGINT, handler) }.unwrap();
-
-    let mut length_buffer: [u8; 4] = [0, 0, 0, 0];
-    let mut got_it: bool = false;
-
-    stream.set_nonblocking(true)?;
-
-    while !got_it {
-        match stream.read_exact(&mut length_buffer) {
-            Ok(_) => got_it = true,
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                if SIGNALED.load(Ordering::Relaxed) == true {
-                    let sig_num: i32 = Signal::SIGINT as i32;
-                    stream.write(&sig_num.to_ne_bytes())?;
-                }
-                thread::sleep(Duration::from_millis(10));
-            }
-            result => result?,
+    match stream.as_unix() {
+        Some(unix_stream) => {
+            unix_stream.send_with_fd(payload.to_string().as_bytes(), &fds[..])?;
+        }
+        None => {
+            warn!("Endpoint does not support fd forwarding, sending payload only");
+            stream.write_all(payload.to_string().as_bytes())?;
         }
     }
 
-    let response_length: i32 = i32::from_ne_bytes(length_buffer);
-
-    debug!("Read response size {:?}", response_length);
-
-    let mut response_buffer: Vec<u8> = std::iter::repeat(0u8)
-        .take(response_length.try_into().unwrap())
-        .collect::<Vec<_>>();
+    // `O_CLOEXEC` on both ends so they don't leak into whatever
+    // `response.replace_process` execs into. `O_NONBLOCK` on both ends too --
+    // the write end matters just as much as the read end, since
+    // `handle_signal` writes to it from signal-handler context and a
+    // blocking write there (e.g. if the pipe backs up) would be unsafe.
+    let (sig_read, sig_write) = unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+    let sig_read_fd: RawFd = sig_read.into_raw_fd();
+    let sig_write_fd: RawFd = sig_write.into_raw_fd();
+    SIGNAL_PIPE_WRITE.store(sig_write_fd, Ordering::Relaxed);
+
+    let handler = SigHandler::Handler(handle_signal);
+    for signal in &config.forwarded_signals {
+        unsafe { signal::signal(*signal, handler) }.unwrap();
+    }
 
-    stream.set_nonblocking(false)?;
-    stream.read_exact(&mut response_buffer)?;
+    const SOCKET_KEY: usize = 1;
+    const SIGNAL_KEY: usize = 2;
 
-    debug!("Read response bytes: {:?}", response_buffer);
+    let poller = Poller::new()?;
+    unsafe {
+        poller.add(stream.as_raw_fd(), Event::readable(SOCKET_KEY))?;
+        poller.add(sig_read_fd, Event::readable(SIGNAL_KEY))?;
+    }
+    let mut events = Events::new();
 
-    let response: server::Response = serde_json::from_slice(&response_buffer)?;
+    stream.set_nonblocking(true)?;
 
-    debug!("Parsed response {:?}", response);
+    // Each frame is a one-byte tag, a 4-byte native-endian length, then that
+    // many bytes of JSON. The server may send any number of `LogRecord`
+    // frames before the terminal `Response` frame.
+    let response: server::Response = loop {
+        let mut tag_buffer = [0u8; 1];
+        read_polled(
+            &mut stream,
+            &poller,
+            &mut events,
+            sig_read_fd,
+            SOCKET_KEY,
+            SIGNAL_KEY,
+            &mut tag_buffer,
+        )?;
+
+        let mut length_buffer = [0u8; 4];
+        read_polled(
+            &mut stream,
+            &poller,
+            &mut events,
+            sig_read_fd,
+            SOCKET_KEY,
+            SIGNAL_KEY,
+            &mut length_buffer,
+        )?;
+        let frame_length = parse_frame_length(length_buffer)?;
+
+        let mut frame_buffer = vec![0u8; frame_length];
+        read_polled(
+            &mut stream,
+            &poller,
+            &mut events,
+            sig_read_fd,
+            SOCKET_KEY,
+            SIGNAL_KEY,
+            &mut frame_buffer,
+        )?;
+
+        match tag_buffer[0] {
+            server::FRAME_LOG_RECORD => {
+                replay_log_record(serde_json::from_slice(&frame_buffer)?);
+            }
+            server::FRAME_RESPONSE => {
+                let response: server::Response = serde_json::from_slice(&frame_buffer)?;
+                debug!("Parsed response {:?}", response);
+                break response;
+            }
+            other => warn!("Ignoring frame with unknown tag {}", other),
+        }
+    };
 
     match response.replace_process {
         Some(rp) => {
@@ -169,3 +357,23 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_frame_length_accepts_zero() {
+        assert_eq!(parse_frame_length(0i32.to_ne_bytes()).unwrap(), 0);
+    }
+
+    #[test]
+    fn parse_frame_length_accepts_positive() {
+        assert_eq!(parse_frame_length(42i32.to_ne_bytes()).unwrap(), 42);
+    }
+
+    #[test]
+    fn parse_frame_length_rejects_negative() {
+        assert!(parse_frame_length((-1i32).to_ne_bytes()).is_err());
+    }
+}
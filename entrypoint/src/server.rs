@@ -1,16 +1,20 @@
 use nix::sys::signal::{self, Signal};
-use nix::unistd::Pid;
+use nix::sys::socket::{self as nix_socket, sockopt, AddressFamily, SockFlag, SockType, UnixAddr};
+use nix::unistd::{geteuid, Pid};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::path::Path;
+use std::path::PathBuf;
 use std::thread;
 use std::time::{Duration, Instant};
 use std::{fs, process};
 // use serde_json::Result;
 
-use crate::config::Config;
+use crate::config::{Config, Endpoint, SOCKET_ENDPOINT_VAR};
 
 const START_CMD_JSON: &str = env!("ENTRYPOINT_START_CMD_JSON");
 const PID_PATH: &str = env!("ENTRYPOINT_PID_PATH");
@@ -27,6 +31,131 @@ struct StartCmd {
     args: Vec<String>,
 }
 
+/// Everything that identifies one particular clavier server: how to start
+/// it, where its PID file lives, and where to reach it. Threading this
+/// through instead of reading the `ENTRYPOINT_*` env! globals directly
+/// means the lifecycle functions below no longer assume a single global
+/// server -- tests can spin one up under a temp dir, and one machine can
+/// host several independently-configured servers at once.
+pub struct ServerContext {
+    pub start_cmd_json: String,
+    pub pid_path: PathBuf,
+    pub socket_endpoint: Endpoint,
+}
+
+impl ServerContext {
+    /// Reproduce today's behavior: the start command, PID path, and socket
+    /// path baked in at compile time via `env!`, with the socket endpoint
+    /// still overridable through `SOCKET_ENDPOINT_VAR`.
+    pub fn from_env() -> ServerContext {
+        ServerContext {
+            start_cmd_json: START_CMD_JSON.to_string(),
+            pid_path: PathBuf::from(PID_PATH),
+            socket_endpoint: Endpoint::from_env(
+                SOCKET_ENDPOINT_VAR,
+                Endpoint::UnixPath(PathBuf::from(SOCKET_PATH)),
+            ),
+        }
+    }
+}
+
+/// A connected control-socket stream, over whichever `Endpoint` the server
+/// was reached at.
+pub enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Unix(s) => s.set_nonblocking(nonblocking),
+            Stream::Tcp(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// The stream as a `UnixStream`, if it is one -- only Unix sockets can
+    /// carry passed file descriptors.
+    pub fn as_unix(&self) -> Option<&UnixStream> {
+        match self {
+            Stream::Unix(s) => Some(s),
+            Stream::Tcp(_) => None,
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Unix(s) => s.as_raw_fd(),
+            Stream::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(s) => s.read(buf),
+            Stream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(s) => s.write(buf),
+            Stream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Unix(s) => s.flush(),
+            Stream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+/// Frame tag written before every length-prefixed message so the client can
+/// tell a forwarded log record from the terminal response without guessing.
+pub const FRAME_LOG_RECORD: u8 = 0;
+pub const FRAME_RESPONSE: u8 = 1;
+
+/// The server's final, length-prefixed answer to a request.
+///
+/// Note for bisecting: `main.rs` has referenced `server::Response` since the
+/// baseline commit, predating this struct's definition here -- that gap is
+/// pre-existing and not introduced by this series.
+#[derive(Debug, Deserialize)]
+pub struct Response {
+    pub exit_status: i32,
+    pub replace_process: Option<ReplaceProcess>,
+}
+
+/// Tells the client to `exec` into another program in its place, e.g. to
+/// hand off to a process that needs a real controlling terminal.
+#[derive(Debug, Deserialize)]
+pub struct ReplaceProcess {
+    pub program: String,
+    pub env: Option<HashMap<String, String>>,
+    pub args: Option<Vec<String>>,
+    pub cwd: Option<String>,
+}
+
+/// A single log line the server wants replayed through the client's own
+/// `log` setup, since the server's real stderr is the client's and bypasses
+/// the client's `env_logger` entirely.
+#[derive(Debug, Deserialize)]
+pub struct LogRecord {
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    pub module_path: Option<String>,
+    pub line: Option<u32>,
+}
+
 // Public API
 // ===========================================================================
 
@@ -48,8 +177,8 @@ pub fn is_kill_arg(arg: &str) -> bool {
     false
 }
 
-pub fn create() -> Result<(), Box<dyn Error>> {
-    let start_cmd: StartCmd = serde_json::from_str(START_CMD_JSON)?;
+pub fn create(ctx: &ServerContext, config: &Config) -> Result<(), Box<dyn Error>> {
+    let start_cmd: StartCmd = serde_json::from_str(&ctx.start_cmd_json)?;
 
     let mut command = process::Command::new(start_cmd.program);
 
@@ -61,31 +190,26 @@ pub fn create() -> Result<(), Box<dyn Error>> {
 
     child.wait()?;
 
-    wait_for_socket_file(10)?;
+    wait_for_endpoint_ready(&ctx.socket_endpoint, 10)?;
 
     Ok(())
 }
 
-pub fn kill(config: &Config) -> Result<(), Box<dyn Error>> {
-    let socket_path = Path::new(SOCKET_PATH);
-
-    if !Path::new(PID_PATH).exists() {
-        info!("Server not running -- not pid file at {:?}", PID_PATH);
+pub fn kill(ctx: &ServerContext, config: &Config) -> Result<(), Box<dyn Error>> {
+    if !ctx.pid_path.exists() {
+        info!("Server not running -- not pid file at {:?}", ctx.pid_path);
         return Ok(());
     }
 
-    let pid = read_pid()?;
+    let pid = read_pid(ctx)?.pid;
 
     if !is_alive(pid) {
         info!(
-            "PID file present at {} but server does not apprear to be alive",
-            PID_PATH
+            "PID file present at {:?} but server does not apprear to be alive",
+            ctx.pid_path
         );
-        remove_pid_file();
-
-        if socket_path.exists() {
-            remove_socket_file();
-        }
+        remove_pid_file(ctx);
+        remove_socket_file(&ctx.socket_endpoint);
 
         return Ok(());
     }
@@ -100,11 +224,8 @@ pub fn kill(config: &Config) -> Result<(), Box<dyn Error>> {
     if let Ok(_) = try_to_kill(&config, pid, Signal::SIGKILL) {
         info!("Killed server.");
 
-        remove_pid_file();
-
-        if socket_path.exists() {
-            remove_socket_file();
-        }
+        remove_pid_file(ctx);
+        remove_socket_file(&ctx.socket_endpoint);
 
         return Ok(());
     }
@@ -112,85 +233,290 @@ pub fn kill(config: &Config) -> Result<(), Box<dyn Error>> {
     Err(format!("Failed to kill server at PID {}", pid).into())
 }
 
-pub fn connect(config: &Config) -> Result<UnixStream, Box<dyn Error>> {
+/// Ask a running server to re-exec itself in place via `SIGHUP`, keeping its
+/// listening socket bound the whole time, instead of the drop-everything
+/// `kill` then `create` dance.
+///
+/// Readiness is detected by watching the PID file's generation counter
+/// advance past what it was before the `SIGHUP`, then confirming a connect
+/// succeeds, within `config.restart_graceful`'s retry budget. If the
+/// generation never advances (e.g. the server is too old to understand
+/// `SIGHUP`-to-re-exec, or it crashed), fall back to the old kill+create
+/// path so `--_RESTART` still works.
+pub fn restart_graceful(ctx: &ServerContext, config: &Config) -> Result<(), Box<dyn Error>> {
+    let pid_file = match read_pid(ctx) {
+        Ok(pid_file) if is_alive(pid_file.pid) => pid_file,
+        _ => {
+            info!("No live server to restart gracefully, falling back to kill+create");
+            kill(ctx, config)?;
+            return create(ctx, config);
+        }
+    };
+
+    info!(
+        "Sending SIGHUP to PID {} (generation {}) to request a graceful re-exec",
+        pid_file.pid, pid_file.generation
+    );
+    signal::kill(pid_file.pid, Signal::SIGHUP)?;
+
+    let t_start = Instant::now();
+    let mut attempt_number: u32 = 0;
+
+    while attempt_number < config.restart_graceful.max_attempts {
+        if let Ok(new_pid_file) = read_pid(ctx) {
+            if new_pid_file.generation > pid_file.generation
+                && connect_and_authorize(ctx, config).is_ok()
+            {
+                info!(
+                    "Server re-exec'd into generation {} after {:?}",
+                    new_pid_file.generation,
+                    t_start.elapsed()
+                );
+                return Ok(());
+            }
+        }
+
+        thread::sleep(config.restart_graceful.sleep_for(attempt_number));
+        attempt_number += 1;
+    }
+
+    warn!(
+        "Server did not advance past generation {} within the retry budget, \
+        falling back to kill+create",
+        pid_file.generation
+    );
+    kill(ctx, config)?;
+    create(ctx, config)
+}
+
+pub fn connect(ctx: &ServerContext, config: &Config) -> Result<Stream, Box<dyn Error>> {
     // First do a single connection attempt, returning then and there if it
     // succeeds (the "happy path").
-    if let Ok(stream) = UnixStream::connect(SOCKET_PATH) {
+    if let Ok(stream) = connect_and_authorize(ctx, config) {
         return Ok(stream);
     }
 
     // We failed to connect. The server may be dead, unresponsive, or something
     // random went wrong. First, see if we know it's PID.
-    if let Ok(pid) = read_pid() {
+    if let Ok(pid) = read_pid(ctx).map(|pid_file| pid_file.pid) {
         // We do know the PID. Next see if it's alive.
         if is_alive(pid) {
             // It's alive, try to connect a few more times
-            if let Ok(stream) = try_to_connect(&config) {
+            if let Ok(stream) = try_to_connect(ctx, config) {
                 return Ok(stream);
             }
 
             // No dice. Kill it and start over.
             warn!(
-                "Failed to connect to server at PID {} through {:?}, \
+                "Failed to connect to server at PID {} through endpoint, \
                 killing...",
-                pid, SOCKET_PATH
+                pid
             );
-            kill(&config)?;
+            kill(ctx, config)?;
         } else {
             info!("PID file is present but server does not seem to be alive");
 
             // Remove the files to get to a clean state.
-            remove_pid_file();
-            remove_socket_file();
+            remove_pid_file(ctx);
+            remove_socket_file(&ctx.socket_endpoint);
         }
     }
 
     info!("Creating a new server...");
-    create()?;
+    create(ctx, config)?;
 
-    if let Ok(stream) = try_to_connect(&config) {
+    if let Ok(stream) = try_to_connect(ctx, config) {
         return Ok(stream);
     }
 
-    Err(format!("Failed to connect to server at {:?}", SOCKET_PATH).into())
+    Err("Failed to connect to server".into())
 }
 
-pub fn socket_exists() -> bool {
-    Path::new(SOCKET_PATH).exists()
+/// Whether the server appears to be listening at `endpoint`.
+///
+/// For a filesystem Unix socket this is a plain stat; abstract and TCP
+/// endpoints have no path to stat, so readiness is instead probed by
+/// attempting (and immediately dropping) a connection.
+pub fn socket_exists(endpoint: &Endpoint) -> bool {
+    match endpoint {
+        Endpoint::UnixPath(path) => path.exists(),
+        Endpoint::AbstractUnix(_) | Endpoint::Tcp(_) => connect_endpoint(endpoint).is_ok(),
+    }
 }
 
-pub fn read_pid() -> Result<Pid, Box<dyn Error>> {
-    let contents = fs::read_to_string(PID_PATH)?;
+/// The running server's PID and restart generation, as recorded in the PID
+/// file.
+pub struct PidFile {
+    pub pid: Pid,
+    pub generation: u64,
+}
+
+/// Parse the PID file, which holds `<pid> <generation>` whitespace-
+/// separated -- `generation` defaults to `0` if it's missing, so a PID file
+/// written before generations existed still reads fine.
+pub fn read_pid(ctx: &ServerContext) -> Result<PidFile, Box<dyn Error>> {
+    let contents = fs::read_to_string(&ctx.pid_path)?;
+    let mut tokens = contents.split_whitespace();
 
-    let pid = contents.trim().parse::<i32>()?;
+    let pid = tokens
+        .next()
+        .ok_or("Empty pid file")?
+        .parse::<i32>()?;
 
     if pid <= 0 {
         return Err("Bad pid in pid file".into());
     }
 
-    Ok(Pid::from_raw(pid))
+    let generation = tokens
+        .next()
+        .map(|token| token.parse::<u64>())
+        .transpose()?
+        .unwrap_or(0);
+
+    Ok(PidFile {
+        pid: Pid::from_raw(pid),
+        generation,
+    })
 }
 
-pub fn remove_pid_file() {
-    info!("Removing PID file at {:?}", PID_PATH);
-    fs::remove_file(PID_PATH).unwrap_or(());
+pub fn remove_pid_file(ctx: &ServerContext) {
+    info!("Removing PID file at {:?}", ctx.pid_path);
+    fs::remove_file(&ctx.pid_path).unwrap_or(());
 }
 
-pub fn remove_socket_file() {
-    info!("Removing socket file at {:?}", SOCKET_PATH);
-    fs::remove_file(SOCKET_PATH).unwrap_or(());
+/// Remove the socket file backing `endpoint`, if it has one (abstract and
+/// TCP endpoints don't).
+pub fn remove_socket_file(endpoint: &Endpoint) {
+    if let Endpoint::UnixPath(path) = endpoint {
+        info!("Removing socket file at {:?}", path);
+        fs::remove_file(path).unwrap_or(());
+    }
 }
 
 // Private Helpers
 // ===========================================================================
 
-fn try_to_connect(config: &Config) -> Result<UnixStream, ()> {
+/// Make a single connection attempt to `endpoint`.
+fn connect_endpoint(endpoint: &Endpoint) -> io::Result<Stream> {
+    match endpoint {
+        Endpoint::UnixPath(path) => UnixStream::connect(path).map(Stream::Unix),
+        Endpoint::AbstractUnix(name) => {
+            debug!(
+                "Connecting to abstract socket {}",
+                crate::config::escape_abstract_name(name)
+            );
+            connect_abstract_unix(name).map(Stream::Unix)
+        }
+        Endpoint::Tcp(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+    }
+}
+
+/// Connect to a Linux abstract-namespace Unix socket, which `UnixStream`
+/// can't address directly since abstract names aren't filesystem paths.
+fn connect_abstract_unix(name: &[u8]) -> io::Result<UnixStream> {
+    let addr = UnixAddr::new_abstract(name).map_err(io::Error::from)?;
+
+    // `UnixStream::connect` and `TcpStream::connect` set `CLOEXEC` on our
+    // behalf for the other endpoint kinds; since we're going around them
+    // here, ask the kernel for
+    // the same so this fd doesn't leak into `replace_process`'s `exec`.
+    let fd = nix_socket::socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .map_err(io::Error::from)?;
+
+    nix_socket::connect(fd, &addr).map_err(io::Error::from)?;
+
+    Ok(unsafe { UnixStream::from_raw_fd(fd) })
+}
+
+/// Connect to `ctx.socket_endpoint`, then -- for Unix sockets, when
+/// `config.require_same_uid` is set -- verify the peer is running as us
+/// before trusting the connection. We forward real stdin/stdout/stderr fds
+/// and the full environment into whatever answers the socket, so a socket
+/// squatted by another user is a privilege-leak vector.
+///
+/// A credential mismatch is surfaced as an ordinary connection failure so
+/// it flows into the same retry/kill/recreate path a dead or unresponsive
+/// server would.
+fn connect_and_authorize(ctx: &ServerContext, config: &Config) -> io::Result<Stream> {
+    let stream = connect_endpoint(&ctx.socket_endpoint)?;
+
+    if config.require_same_uid {
+        match &stream {
+            Stream::Unix(unix_stream) => match check_peer(unix_stream) {
+                Ok(peer) => authorize_peer_uid(&peer, geteuid().as_raw())?,
+                Err(e) => {
+                    warn!("Failed to read peer credentials: {}", e);
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "peer credentials unavailable",
+                    ));
+                }
+            },
+            // `SO_PEERCRED` has no TCP equivalent, so `require_same_uid`
+            // provides no protection here -- surface that loudly rather
+            // than silently no-op'ing through a flag the caller opted into.
+            Stream::Tcp(_) => {
+                warn!(
+                    "require_same_uid is set but the endpoint is TCP, which has no \
+                    SO_PEERCRED equivalent -- this provides no protection"
+                );
+            }
+        }
+    }
+
+    Ok(stream)
+}
+
+/// Decide whether `peer`'s UID is acceptable, given our own `our_uid`. Pulled
+/// out of `connect_and_authorize` so the UID-matching decision can be tested
+/// without needing a real socket to get a `PeerCred` from.
+fn authorize_peer_uid(peer: &PeerCred, our_uid: u32) -> io::Result<()> {
+    if peer.uid == our_uid {
+        return Ok(());
+    }
+
+    warn!(
+        "Refusing socket: peer UID {} does not match our UID {}",
+        peer.uid, our_uid
+    );
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "peer UID mismatch",
+    ))
+}
+
+/// Fetch the credentials of the process on the other end of `stream` via
+/// `SO_PEERCRED`.
+pub fn check_peer(stream: &UnixStream) -> Result<PeerCred, Box<dyn Error>> {
+    let creds = nix_socket::getsockopt(stream, sockopt::PeerCredentials)?;
+
+    Ok(PeerCred {
+        pid: creds.pid(),
+        uid: creds.uid(),
+        gid: creds.gid(),
+    })
+}
+
+/// Credentials of the process on the other end of a `UnixStream`, as
+/// reported by the kernel (not self-asserted by the peer).
+pub struct PeerCred {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+fn try_to_connect(ctx: &ServerContext, config: &Config) -> Result<Stream, ()> {
     let t_start = Instant::now();
 
     let mut attempt_number: u32 = 0;
 
     while attempt_number < config.connect_server.max_attempts {
-        if let Ok(stream) = UnixStream::connect(SOCKET_PATH) {
+        if let Ok(stream) = connect_and_authorize(ctx, config) {
             return Ok(stream);
         }
 
@@ -201,7 +527,7 @@ fn try_to_connect(config: &Config) -> Result<UnixStream, ()> {
 
     let delta_t = t_start.elapsed();
 
-    warn!("Failed to connect to server at socket {:?}", SOCKET_PATH);
+    warn!("Failed to connect to server");
     warn!(
         "Made {} attempts over {:?} seconds",
         attempt_number, delta_t
@@ -252,13 +578,12 @@ fn try_to_kill(config: &Config, pid: Pid, signal: Signal) -> Result<(), ()> {
     Err(())
 }
 
-fn wait_for_socket_file(max_attempts: usize) -> Result<(), &'static str> {
+fn wait_for_endpoint_ready(endpoint: &Endpoint, max_attempts: usize) -> Result<(), &'static str> {
     let mut attempt_number: usize = 0;
     let dur = Duration::from_millis(100);
-    let socket_path = Path::new(SOCKET_PATH);
 
     while attempt_number < max_attempts {
-        if socket_path.exists() {
+        if socket_exists(endpoint) {
             return Ok(());
         }
 
@@ -268,3 +593,29 @@ fn wait_for_socket_file(max_attempts: usize) -> Result<(), &'static str> {
 
     Err("Socket file never appeared")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorize_peer_uid_accepts_matching_uid() {
+        let peer = PeerCred {
+            pid: 1234,
+            uid: 1000,
+            gid: 1000,
+        };
+        assert!(authorize_peer_uid(&peer, 1000).is_ok());
+    }
+
+    #[test]
+    fn authorize_peer_uid_rejects_mismatched_uid() {
+        let peer = PeerCred {
+            pid: 1234,
+            uid: 1000,
+            gid: 1000,
+        };
+        let err = authorize_peer_uid(&peer, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}